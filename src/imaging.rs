@@ -0,0 +1,144 @@
+//! Optional validation/transcode stage applied to downloaded bytes before
+//! they're handed to a [`MediaStore`](crate::storage::MediaStore). Disabled
+//! by default so a plain run keeps streaming straight to storage.
+
+use image::{GenericImageView, ImageFormat};
+
+/// Decoded metadata recorded in the product's `extra` map.
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+}
+
+/// The bytes to actually store, the extension they should be stored under,
+/// and the metadata describing them.
+pub struct ProcessedImage {
+    pub bytes: Vec<u8>,
+    pub ext: &'static str,
+    pub info: ImageInfo,
+}
+
+/// How the validation/transcode stage behaves, built once from CLI flags and
+/// threaded down to each download.
+pub struct ImagingConfig {
+    pub transcode_webp: bool,
+    pub webp_quality: f32,
+}
+
+/// Decode `bytes` to confirm they form a valid image, optionally re-encoding
+/// to WebP at `config.webp_quality`. Returns an error if the bytes don't
+/// decode as an image at all; callers should treat that as non-retryable.
+pub fn process(bytes: &[u8], config: &ImagingConfig) -> Result<ProcessedImage, image::ImageError> {
+    let img = image::load_from_memory(bytes)?;
+    let (width, height) = img.dimensions();
+
+    if !config.transcode_webp {
+        let format = image::guess_format(bytes).unwrap_or(ImageFormat::Jpeg);
+        return Ok(ProcessedImage {
+            bytes: bytes.to_vec(),
+            ext: ext_for_format(format),
+            info: ImageInfo {
+                width,
+                height,
+                format: format_name(format).to_string(),
+            },
+        });
+    }
+
+    let rgba = img.to_rgba8();
+    let encoded = webp::Encoder::from_rgba(&rgba, width, height).encode(config.webp_quality);
+    Ok(ProcessedImage {
+        bytes: encoded.to_vec(),
+        ext: "webp",
+        info: ImageInfo {
+            width,
+            height,
+            format: "webp".to_string(),
+        },
+    })
+}
+
+fn ext_for_format(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "png",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Gif => "gif",
+        ImageFormat::Bmp => "bmp",
+        _ => "jpg",
+    }
+}
+
+fn format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Png => "png",
+        ImageFormat::WebP => "webp",
+        ImageFormat::Gif => "gif",
+        ImageFormat::Bmp => "bmp",
+        ImageFormat::Jpeg => "jpeg",
+        _ => "jpeg",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A valid, minimal 1x1 grayscale+alpha PNG.
+    const ONE_PIXEL_PNG: &[u8] = &[
+        137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 1, 0, 0, 0, 1, 8, 4,
+        0, 0, 0, 181, 28, 12, 2, 0, 0, 0, 11, 73, 68, 65, 84, 120, 218, 99, 100, 248, 15, 0, 1, 5,
+        1, 1, 39, 24, 227, 102, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130,
+    ];
+
+    #[test]
+    fn ext_for_format_known_variants() {
+        assert_eq!(ext_for_format(ImageFormat::Png), "png");
+        assert_eq!(ext_for_format(ImageFormat::WebP), "webp");
+        assert_eq!(ext_for_format(ImageFormat::Gif), "gif");
+        assert_eq!(ext_for_format(ImageFormat::Bmp), "bmp");
+    }
+
+    #[test]
+    fn ext_for_format_defaults_to_jpg() {
+        assert_eq!(ext_for_format(ImageFormat::Jpeg), "jpg");
+        assert_eq!(ext_for_format(ImageFormat::Tiff), "jpg");
+    }
+
+    #[test]
+    fn format_name_known_variants() {
+        assert_eq!(format_name(ImageFormat::Png), "png");
+        assert_eq!(format_name(ImageFormat::WebP), "webp");
+        assert_eq!(format_name(ImageFormat::Gif), "gif");
+        assert_eq!(format_name(ImageFormat::Bmp), "bmp");
+        assert_eq!(format_name(ImageFormat::Jpeg), "jpeg");
+    }
+
+    #[test]
+    fn format_name_defaults_to_jpeg() {
+        assert_eq!(format_name(ImageFormat::Tiff), "jpeg");
+    }
+
+    #[test]
+    fn process_without_transcode_keeps_original_bytes_and_format() {
+        let config = ImagingConfig {
+            transcode_webp: false,
+            webp_quality: 80.0,
+        };
+        let processed = process(ONE_PIXEL_PNG, &config).unwrap();
+        assert_eq!(processed.bytes, ONE_PIXEL_PNG);
+        assert_eq!(processed.ext, "png");
+        assert_eq!(processed.info.width, 1);
+        assert_eq!(processed.info.height, 1);
+        assert_eq!(processed.info.format, "png");
+    }
+
+    #[test]
+    fn process_rejects_invalid_bytes() {
+        let config = ImagingConfig {
+            transcode_webp: false,
+            webp_quality: 80.0,
+        };
+        assert!(process(b"not an image", &config).is_err());
+    }
+}
@@ -3,17 +3,27 @@ use std::{
     env,
     fs::File,
     io::Write,
-    path::{Path, PathBuf},
+    path::PathBuf,
+    pin::Pin,
     sync::{
-        Arc,
         atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
     },
+    time::Duration,
 };
 
-use futures::stream::{FuturesUnordered, StreamExt};
+use bytes::Bytes;
+use futures::stream::StreamExt;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio::fs;
 
+mod imaging;
+mod storage;
+
+use imaging::{ImageInfo, ImagingConfig};
+use storage::{FileStore, MediaStore, S3Store};
+
 /// Product as stored in JSON.
 ///
 /// `$iFrame_IMAGE` in the input is read into `original_image_url` and is
@@ -41,95 +51,289 @@ struct Product {
     extra: HashMap<String, serde_json::Value>,
 }
 
+/// Default number of downloads allowed to be in flight at once.
+const DEFAULT_CONCURRENCY: usize = 32;
+
+/// Default number of retry attempts for a failed download, on top of the
+/// initial attempt.
+const DEFAULT_RETRIES: usize = 5;
+
+/// Initial delay before the first retry; doubles on each subsequent attempt.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound on the retry backoff delay.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Root folder for content-addressed storage.
+const CONTENT_ADDRESS_FOLDER: &str = "images";
+
+/// Where the persistent download cache (ETag/Last-Modified/hash per URL) is
+/// stored on disk.
+const CACHE_DB_PATH: &str = "download-cache.sled";
+
+/// Default WebP quality used when `--transcode-webp` is passed without
+/// `--webp-quality`.
+const DEFAULT_WEBP_QUALITY: f32 = 80.0;
+
+/// What we remember about a previously-downloaded URL, keyed by
+/// `original_image_url`.
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    path: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    hash: Option<String>,
+    /// The public URL the storage backend served this object from. Absent
+    /// on entries written before storage backends became pluggable.
+    #[serde(default)]
+    store_url: Option<String>,
+}
+
+/// The cache handle. `sled::Db` is cheaply cloneable and safe to share across
+/// concurrently-polled tasks, so it's opened once here and reused for every
+/// lookup/write rather than reopened per download.
+fn cache_db() -> &'static sled::Db {
+    static DB: OnceLock<sled::Db> = OnceLock::new();
+    DB.get_or_init(|| sled::open(CACHE_DB_PATH).expect("failed to open download cache"))
+}
+
+/// Look up the cache entry for `url`, if any.
+fn get_cache_entry(url: &str) -> Result<Option<CacheEntry>, Box<dyn std::error::Error>> {
+    match cache_db().get(url)? {
+        Some(ivec) => Ok(Some(serde_json::from_slice(&ivec)?)),
+        None => Ok(None),
+    }
+}
+
+/// Record (or overwrite) the cache entry for `url`.
+fn put_cache_entry(url: &str, entry: &CacheEntry) -> Result<(), Box<dyn std::error::Error>> {
+    cache_db().insert(url, serde_json::to_vec(entry)?)?;
+    Ok(())
+}
+
+/// A single download to perform, or a cache hit that needs no download.
+enum Job {
+    /// Download to a backend-relative key; `new_image_url` is overridden
+    /// from the storage backend's reported URL once known.
+    Plain {
+        index: usize,
+        url: String,
+        filepath: String,
+    },
+    /// Content-addressed download: the final path depends on the hash of the
+    /// downloaded bytes, so it isn't known until the download completes.
+    ContentAddressed { index: usize, url: String },
+    /// The product's `dbhash` already maps to a stored file; no download
+    /// needed, just re-point `new_image_url` at it.
+    AlreadyCached {
+        index: usize,
+        url: String,
+        dbhash: String,
+        path: String,
+    },
+}
+
+/// What changes on `products[index]` once a job resolves: `new_image_url`
+/// always, `dbhash` only for content-addressed jobs, and (when the imaging
+/// stage ran) the decoded image's dimensions/format.
+struct ProductUpdate {
+    dbhash: Option<String>,
+    new_image_url: String,
+    image_info: Option<ImageInfo>,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Location of the executable
     let exe_dir: PathBuf = env::current_exe()?.parent().unwrap().to_path_buf();
 
-    // JSON file passed as the first command-line argument
+    // JSON file passed as the first positional argument, plus the optional
+    // flags `parse_args` accepts (`--concurrency N` caps downloads in flight).
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <json-file>", args[0]);
-        std::process::exit(1);
-    }
-    let json_file_name = &args[1];
-    let json_path = exe_dir.join(json_file_name);
+    let cli = match parse_args(&args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let json_path = exe_dir.join(&cli.json_file_name);
+    let store = build_store(&cli.storage_backend)?;
 
     // Load the JSON file
     let data = fs::read_to_string(&json_path).await?;
     let mut products: Vec<Product> = serde_json::from_str(&data)?;
 
-    // Prepare the new URLs that will be written to the modified JSON
-    const SERVER_IMAGES_FOLDER: &str = "images";
-    for p in &mut products {
-        if p.original_image_url.is_some() {
-            let brandfolder = p
-                .extra
-                .get("$iBrand")
-                .and_then(|v| v.as_str())
-                .unwrap_or("")
-                .replace(' ', "_")
-                .replace('\'', "")
-                .to_lowercase();
-            p.new_image_url = Some(format!(
-                "https://portal.framescloud.optiserver.co.uk/{}/{}/{}",
-                SERVER_IMAGES_FOLDER,
-                brandfolder,
-                p.localfilename
-            ));
-        }
-    }
-
     let client = reqwest::Client::new();
     let total = products.len();
     let counter = Arc::new(AtomicUsize::new(0));
-    let mut tasks = FuturesUnordered::new();
 
-    // Download each image concurrently
-    for product in products.clone() {
-        if let Some(url) = product.original_image_url.clone() {
+    // Work out what needs downloading and where to before fanning out, since
+    // directory creation (and, in content-addressed mode, the dbhash cache
+    // lookup) needs to happen up front either way.
+    let mut jobs = Vec::new();
+    for (index, product) in products.iter().enumerate() {
+        let Some(url) = product.original_image_url.clone() else {
+            continue;
+        };
+
+        if cli.content_addressed {
+            // `find_content_addressed` scans the local filesystem directly,
+            // so it can only short-circuit a re-download when the configured
+            // backend actually writes there; against S3 it would always miss
+            // and every known-dbhash product would be re-fetched for
+            // nothing. `download_once_content_addressed`'s `store.exists`
+            // check still dedupes the final upload either way, just after
+            // paying for the re-download.
+            let local_backend = matches!(cli.storage_backend, StorageBackend::File);
+            if local_backend && !product.dbhash.is_empty() {
+                if let Some(existing) = find_content_addressed(&product.dbhash).await? {
+                    jobs.push(Job::AlreadyCached {
+                        index,
+                        url,
+                        dbhash: product.dbhash.clone(),
+                        path: existing,
+                    });
+                    continue;
+                }
+            }
+            jobs.push(Job::ContentAddressed { index, url });
+        } else {
             let filename = if product.localfilename.is_empty() {
                 format!("{}.jpg", product.dbhash)
             } else {
                 product.localfilename.clone()
             };
+            let filename = if cli.imaging.as_ref().is_some_and(|cfg| cfg.transcode_webp) {
+                with_ext(&filename, "webp")
+            } else {
+                filename
+            };
 
             if let Some(brand) = product.extra.get("$iBrand").and_then(|v| v.as_str()) {
-                let brandfolder = brand
-                    .replace(' ', "_")
-                    .replace('\'', "")
-                    .to_lowercase();
+                let brandfolder = brand.replace(' ', "_").replace('\'', "").to_lowercase();
                 tokio::fs::create_dir_all(&brandfolder).await?;
                 let filepath = format!("{}/{}", brandfolder, filename);
+                jobs.push(Job::Plain {
+                    index,
+                    url,
+                    filepath,
+                });
+            }
+        }
+    }
 
-                let client = client.clone();
-                let counter = counter.clone();
-                let total = total;
-
-                tasks.push(tokio::spawn(async move {
-                    match maybe_download(&client, &url, &filepath).await {
-                        Ok(true) => {
-                            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
-                            println!("✅ Downloaded {n} of {total}: {url} → {filepath}");
+    // Download with at most `concurrency` requests in flight at once, so we
+    // don't exhaust sockets/file descriptors on large inputs. Progress is
+    // printed as each job resolves (inside the closure below) rather than
+    // after the whole stream drains, so large inputs show live feedback.
+    let outcomes = futures::stream::iter(jobs)
+        .map(|job| {
+            let client = client.clone();
+            let counter = counter.clone();
+            async move {
+                match job {
+                    Job::Plain {
+                        index,
+                        url,
+                        filepath,
+                    } => {
+                        let result = maybe_download(
+                            store.as_ref(),
+                            &client,
+                            &url,
+                            &filepath,
+                            cli.retries,
+                            cli.imaging.as_ref(),
+                        )
+                        .await;
+                        match result {
+                            Ok(outcome) => {
+                                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                                report_outcome(n, total, outcome.downloaded, &url, &filepath);
+                                let update = outcome.store_url.map(|new_image_url| ProductUpdate {
+                                    dbhash: None,
+                                    new_image_url,
+                                    image_info: outcome.image_info,
+                                });
+                                (index, update)
+                            }
+                            Err(e) => {
+                                eprintln!("❌ Failed {url}: {e}");
+                                (index, None)
+                            }
                         }
-                        Ok(false) => {
-                            let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
-                            println!("⏩ Skipped {n} of {total}: {filepath}, already exists");
+                    }
+                    Job::ContentAddressed { index, url } => {
+                        let result = maybe_download_content_addressed(
+                            store.as_ref(),
+                            &client,
+                            &url,
+                            cli.retries,
+                            cli.imaging.as_ref(),
+                        )
+                        .await;
+                        match result {
+                            Ok(outcome) => {
+                                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                                report_outcome(n, total, outcome.downloaded, &url, &outcome.path);
+                                let update = Some(ProductUpdate {
+                                    dbhash: Some(outcome.hash),
+                                    new_image_url: outcome.store_url,
+                                    image_info: outcome.image_info,
+                                });
+                                (index, update)
+                            }
+                            Err(e) => {
+                                eprintln!("❌ Failed {url}: {e}");
+                                (index, None)
+                            }
                         }
-                        Err(e) => eprintln!("❌ Failed {url}: {e}"),
                     }
-                }));
+                    Job::AlreadyCached {
+                        index,
+                        url,
+                        dbhash,
+                        path,
+                    } => {
+                        let new_image_url = store.public_url(&path);
+                        let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                        report_outcome(n, total, false, &url, &path);
+                        (
+                            index,
+                            Some(ProductUpdate {
+                                dbhash: Some(dbhash),
+                                new_image_url,
+                                image_info: None,
+                            }),
+                        )
+                    }
+                }
+            }
+        })
+        .buffer_unordered(cli.concurrency)
+        .collect::<Vec<_>>()
+        .await;
+
+    for (index, update) in outcomes {
+        if let Some(update) = update {
+            if let Some(dbhash) = update.dbhash {
+                products[index].dbhash = dbhash;
+            }
+            products[index].new_image_url = Some(update.new_image_url);
+            if let Some(info) = update.image_info {
+                let extra = &mut products[index].extra;
+                extra.insert("image_width".to_string(), serde_json::json!(info.width));
+                extra.insert("image_height".to_string(), serde_json::json!(info.height));
+                extra.insert("image_format".to_string(), serde_json::json!(info.format));
             }
         }
     }
 
-    // Wait for all downloads to finish
-    while let Some(_) = tasks.next().await {}
-
     // Write the modified JSON with new URLs
     let new_name = format!(
         "{}-modified-w-embedded-imgs.json",
-        json_file_name.trim_end_matches(".json")
+        cli.json_file_name.trim_end_matches(".json")
     );
     let new_path = exe_dir.join(new_name);
     let mut out_file = File::create(&new_path)?;
@@ -141,24 +345,643 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-/// Download `url` to `filename` unless it already exists.
-/// Returns Ok(true) if a download occurred, Ok(false) if skipped.
+/// Parsed command-line configuration for a run.
+struct CliArgs {
+    json_file_name: String,
+    concurrency: usize,
+    retries: usize,
+    content_addressed: bool,
+    storage_backend: StorageBackend,
+    imaging: Option<ImagingConfig>,
+}
+
+/// Which [`MediaStore`] to persist downloads to. Selected via `--storage` or
+/// the `MEDIA_STORE` environment variable; defaults to `File`.
+enum StorageBackend {
+    File,
+    S3,
+}
+
+/// Parse `<json-file> [--concurrency N] [--retries N] [--content-addressed]
+/// [--storage file|s3] [--validate-images] [--transcode-webp]
+/// [--webp-quality N]` from the raw process arguments.
+fn parse_args(args: &[String]) -> Result<CliArgs, Box<dyn std::error::Error>> {
+    let mut json_file_name = None;
+    let mut concurrency = DEFAULT_CONCURRENCY;
+    let mut retries = DEFAULT_RETRIES;
+    let mut content_addressed = false;
+    let mut storage_backend = match env::var("MEDIA_STORE").as_deref() {
+        Ok("s3") => StorageBackend::S3,
+        _ => StorageBackend::File,
+    };
+    let mut validate_images = false;
+    let mut transcode_webp = false;
+    let mut webp_quality = DEFAULT_WEBP_QUALITY;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--concurrency" => {
+                let value = iter.next().ok_or("--concurrency requires a value")?;
+                concurrency = value.parse()?;
+                if concurrency == 0 {
+                    return Err("--concurrency must be at least 1".into());
+                }
+            }
+            "--retries" => {
+                let value = iter.next().ok_or("--retries requires a value")?;
+                retries = value.parse()?;
+            }
+            "--content-addressed" => content_addressed = true,
+            "--storage" => {
+                let value = iter.next().ok_or("--storage requires a value")?;
+                storage_backend = match value.as_str() {
+                    "file" => StorageBackend::File,
+                    "s3" => StorageBackend::S3,
+                    other => return Err(format!("unknown storage backend: {other}").into()),
+                };
+            }
+            "--validate-images" => validate_images = true,
+            "--transcode-webp" => {
+                validate_images = true;
+                transcode_webp = true;
+            }
+            "--webp-quality" => {
+                let value = iter.next().ok_or("--webp-quality requires a value")?;
+                webp_quality = value.parse()?;
+            }
+            other if json_file_name.is_none() => json_file_name = Some(other.to_string()),
+            other => return Err(format!("unexpected argument: {other}").into()),
+        }
+    }
+
+    let json_file_name = json_file_name.ok_or_else(|| {
+        format!(
+            "Usage: {} <json-file> [--concurrency N] [--retries N] [--content-addressed] \
+             [--storage file|s3] [--validate-images] [--transcode-webp] [--webp-quality N]",
+            args[0]
+        )
+    })?;
+    let imaging = validate_images.then_some(ImagingConfig {
+        transcode_webp,
+        webp_quality,
+    });
+    Ok(CliArgs {
+        json_file_name,
+        concurrency,
+        retries,
+        content_addressed,
+        storage_backend,
+        imaging,
+    })
+}
+
+/// The next backoff delay after a failed attempt: double, capped at
+/// `MAX_RETRY_DELAY`.
+fn next_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RETRY_DELAY)
+}
+
+/// Print a job's outcome as it resolves: `n`/`total` is the running count of
+/// completed jobs (successes and skips alike), `label` is the path the bytes
+/// ended up (or already existed) under.
+fn report_outcome(n: usize, total: usize, downloaded: bool, url: &str, label: &str) {
+    if downloaded {
+        println!("✅ Downloaded {n} of {total}: {url} → {label}");
+    } else {
+        println!("⏩ Skipped {n} of {total}: {label}, already exists");
+    }
+}
+
+/// Replace `name`'s extension with `ext` (appending one if it had none).
+fn with_ext(name: &str, ext: &str) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, _)) => format!("{stem}.{ext}"),
+        None => format!("{name}.{ext}"),
+    }
+}
+
+/// Wrap already-buffered `bytes` as the single-chunk stream `MediaStore`
+/// implementations expect.
+fn once_stream(
+    bytes: Vec<u8>,
+) -> Pin<Box<dyn futures::Stream<Item = Result<Bytes, reqwest::Error>> + Send>> {
+    Box::pin(futures::stream::once(async move {
+        Ok::<Bytes, reqwest::Error>(Bytes::from(bytes))
+    }))
+}
+
+/// Build the configured [`MediaStore`], reading S3 settings (`S3_BUCKET`,
+/// and optionally `S3_PUBLIC_URL_BASE`) from the environment when needed.
+fn build_store(
+    backend: &StorageBackend,
+) -> Result<Box<dyn MediaStore>, Box<dyn std::error::Error>> {
+    match backend {
+        StorageBackend::File => Ok(Box::new(FileStore {
+            public_url_base: "https://portal.framescloud.optiserver.co.uk/images".to_string(),
+        })),
+        StorageBackend::S3 => {
+            let bucket = env::var("S3_BUCKET")
+                .map_err(|_| "S3_BUCKET must be set when --storage s3 is used")?;
+            let public_url_base = env::var("S3_PUBLIC_URL_BASE")
+                .unwrap_or_else(|_| format!("https://{bucket}.s3.amazonaws.com"));
+            Ok(Box::new(S3Store::new(&bucket, public_url_base)?))
+        }
+    }
+}
+
+/// Result of a (possibly skipped) plain download.
+struct DownloadOutcome {
+    downloaded: bool,
+    store_url: Option<String>,
+    image_info: Option<ImageInfo>,
+}
+
+/// Download `url` into `filename` via `store` unless the persistent cache
+/// says it hasn't changed since last time. `downloaded` is false on a
+/// `304 Not Modified` skip.
+///
+/// Retries connection errors and 5xx responses up to `max_retries` times,
+/// with an exponential backoff delay starting at `INITIAL_RETRY_DELAY` and
+/// capped at `MAX_RETRY_DELAY`. A decode failure from `imaging` is never
+/// retried, since the bytes won't change on a retry.
 async fn maybe_download(
+    store: &dyn MediaStore,
+    client: &reqwest::Client,
+    url: &str,
+    filename: &str,
+    max_retries: usize,
+    imaging: Option<&ImagingConfig>,
+) -> Result<DownloadOutcome, Box<dyn std::error::Error>> {
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match download_once(store, client, url, filename, imaging).await {
+            Ok(outcome) => return Ok(outcome),
+            Err(e) if attempt < max_retries && is_retryable(e.as_ref()) => {
+                eprintln!(
+                    "⚠️ Retrying {url} after error: {e} (attempt {})",
+                    attempt + 1
+                );
+                tokio::time::sleep(delay).await;
+                delay = next_delay(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether a download error is worth retrying (connection-level failures,
+/// including a connection dropped mid-body-read, and 5xx responses), as
+/// opposed to e.g. a 4xx that will never succeed.
+fn is_retryable(err: &(dyn std::error::Error + 'static)) -> bool {
+    if err.downcast_ref::<NonRetryableError>().is_some() {
+        return false;
+    }
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(e) => {
+            e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() || e.is_decode()
+        }
+        None => true,
+    }
+}
+
+/// Fetch `url` and hand its bytes to `store` under the key `filename`. How
+/// the write happens is up to the backend: `FileStore` writes to
+/// `<filename>.tmp` and renames it into place on success, so a half-written
+/// file is never observed at the final path.
+///
+/// Sends `If-None-Match`/`If-Modified-Since` from the persistent cache entry
+/// for `url` (if the cached file is still stored), and treats a `304 Not
+/// Modified` response as nothing-to-do. The cache is only updated on a `200`.
+///
+/// `store_url` on the returned outcome is the backend's public URL for the
+/// object, recovered from the cache on a skip.
+///
+/// When `imaging` is set, the whole response is buffered so it can be
+/// decoded/transcoded before being handed to `store`; otherwise the bytes are
+/// streamed straight through, as before.
+async fn download_once(
+    store: &dyn MediaStore,
     client: &reqwest::Client,
     url: &str,
     filename: &str,
-) -> Result<bool, Box<dyn std::error::Error>> {
-    if fs::try_exists(filename).await? {
-        return Ok(false);
+    imaging: Option<&ImagingConfig>,
+) -> Result<DownloadOutcome, Box<dyn std::error::Error>> {
+    let cached = get_cache_entry(url)?;
+    let mut revalidate = None;
+    if let Some(entry) = &cached {
+        if store.exists(&entry.path).await? {
+            revalidate = Some(entry);
+        }
+    }
+
+    let mut req = client.get(url);
+    if let Some(entry) = revalidate {
+        if let Some(etag) = &entry.etag {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified.as_str());
+        }
+    }
+
+    let resp = req.send().await?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(DownloadOutcome {
+            downloaded: false,
+            store_url: cached.and_then(|e| e.store_url),
+            image_info: None,
+        });
+    }
+    if status.is_server_error() {
+        return Err(format!("HTTP error {status}").into());
+    }
+    if !status.is_success() {
+        return Err(NonRetryableError(format!("HTTP error {status}")).into());
+    }
+
+    let etag = header_str(&resp, reqwest::header::ETAG);
+    let last_modified = header_str(&resp, reqwest::header::LAST_MODIFIED);
+
+    let (stored, hash, image_info) = if let Some(cfg) = imaging {
+        let body = resp.bytes().await?;
+        let processed = imaging::process(&body, cfg)
+            .map_err(|e| NonRetryableError(format!("invalid image at {url}: {e}")))?;
+        let hash = format!("{:x}", Sha256::digest(&processed.bytes));
+        let stored = store.store(filename, once_stream(processed.bytes)).await?;
+        (stored, hash, Some(processed.info))
+    } else {
+        // Hash the bytes as they flow through to the store, without
+        // buffering the whole response in memory.
+        let hasher = Arc::new(std::sync::Mutex::new(Sha256::new()));
+        let hasher_tap = hasher.clone();
+        let stream = resp.bytes_stream().map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                hasher_tap.lock().unwrap().update(bytes);
+            }
+            chunk
+        });
+        let stored = store.store(filename, Box::pin(stream)).await?;
+        let hash = format!("{:x}", hasher.lock().unwrap().clone().finalize());
+        (stored, hash, None)
+    };
+
+    put_cache_entry(
+        url,
+        &CacheEntry {
+            path: filename.to_string(),
+            etag,
+            last_modified,
+            hash: Some(hash),
+            store_url: Some(stored.url.clone()),
+        },
+    )?;
+
+    Ok(DownloadOutcome {
+        downloaded: true,
+        store_url: Some(stored.url),
+        image_info,
+    })
+}
+
+/// Read a header's value as an owned `String`, if present and valid UTF-8.
+fn header_str(resp: &reqwest::Response, name: reqwest::header::HeaderName) -> Option<String> {
+    resp.headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+/// Look for an already-stored content-addressed file for `hash`, regardless
+/// of its extension. Returns the path relative to the working directory.
+///
+/// `hash` here is a product's `dbhash` straight from the input JSON, so it
+/// isn't guaranteed to be a well-formed hex digest; a value that isn't
+/// byte-sliceable at the folder boundaries (e.g. non-ASCII) is treated as a
+/// cache miss rather than panicking the whole batch.
+async fn find_content_addressed(hash: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Some(dir) = content_address_dir(hash) else {
+        eprintln!("⚠️ Skipping content-addressed lookup for non-ASCII-safe dbhash {hash:?}");
+        return Ok(None);
+    };
+    let mut entries = match fs::read_dir(&dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.file_stem().and_then(|s| s.to_str()) == Some(hash) {
+            return Ok(path.to_str().map(|s| s.to_string()));
+        }
     }
+    Ok(None)
+}
+
+/// The `images/<aa>/<bb>` directory a hash's content-addressed file lives
+/// under. `None` if `hash` can't be sliced at those byte offsets without
+/// landing mid-character (raw byte indexing would otherwise panic on a
+/// non-ASCII `hash`).
+fn content_address_dir(hash: &str) -> Option<String> {
+    let a = hash.get(0..2.min(hash.len()))?;
+    let start = 2.min(hash.len());
+    let b = hash.get(start..4.min(hash.len()))?;
+    Some(format!("{CONTENT_ADDRESS_FOLDER}/{a}/{b}"))
+}
+
+/// Mime-type sidecar recorded alongside each content-addressed file.
+#[derive(Serialize)]
+struct ContentMetadata<'a> {
+    mime: &'a str,
+    bytes: u64,
+    original_url: &'a str,
+}
+
+/// Result of a (possibly deduped) content-addressed download.
+struct ContentAddressedOutcome {
+    downloaded: bool,
+    path: String,
+    store_url: String,
+    hash: String,
+    image_info: Option<ImageInfo>,
+}
 
-    let resp = client.get(url).send().await?;
-    if !resp.status().is_success() {
-        return Err(format!("HTTP error {}", resp.status()).into());
+/// Download `url`, content-addressing it by the SHA-256 of its bytes, and
+/// persist it through `store`. `downloaded` is false if an identical file
+/// was already stored under a different product.
+async fn maybe_download_content_addressed(
+    store: &dyn MediaStore,
+    client: &reqwest::Client,
+    url: &str,
+    max_retries: usize,
+    imaging: Option<&ImagingConfig>,
+) -> Result<ContentAddressedOutcome, Box<dyn std::error::Error>> {
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        match download_once_content_addressed(store, client, url, imaging).await {
+            Ok(outcome) => return Ok(outcome),
+            Err(e) if attempt < max_retries && is_retryable(e.as_ref()) => {
+                eprintln!(
+                    "⚠️ Retrying {url} after error: {e} (attempt {})",
+                    attempt + 1
+                );
+                tokio::time::sleep(delay).await;
+                delay = next_delay(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Single-attempt implementation of [`maybe_download_content_addressed`].
+///
+/// Sends `If-None-Match`/`If-Modified-Since` from the persistent cache entry
+/// for `url` (if the previously-stored file is still present), same as
+/// [`download_once`]; a `304 Not Modified` response is treated as a skip
+/// using the cached path/hash, with no bytes read.
+///
+/// On a `200`, the response is always buffered in memory: the storage key
+/// depends on the hash of the full body, so it can't be chosen (and handed to
+/// `store`) before every byte has been read.
+async fn download_once_content_addressed(
+    store: &dyn MediaStore,
+    client: &reqwest::Client,
+    url: &str,
+    imaging: Option<&ImagingConfig>,
+) -> Result<ContentAddressedOutcome, Box<dyn std::error::Error>> {
+    let cached = get_cache_entry(url)?;
+    let mut revalidate = None;
+    if let Some(entry) = &cached {
+        if store.exists(&entry.path).await? {
+            revalidate = Some(entry);
+        }
+    }
+
+    let mut req = client.get(url);
+    if let Some(entry) = revalidate {
+        if let Some(etag) = &entry.etag {
+            req = req.header(reqwest::header::IF_NONE_MATCH, etag.as_str());
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            req = req.header(reqwest::header::IF_MODIFIED_SINCE, last_modified.as_str());
+        }
     }
 
-    let bytes = resp.bytes().await?;
-    let mut file = File::create(Path::new(filename))?;
-    file.write_all(&bytes)?;
-    Ok(true)
+    let resp = req.send().await?;
+    let status = resp.status();
+    if status == reqwest::StatusCode::NOT_MODIFIED {
+        let entry = cached.ok_or_else(|| {
+            NonRetryableError(format!("304 Not Modified for {url} with no cache entry"))
+        })?;
+        return Ok(ContentAddressedOutcome {
+            downloaded: false,
+            store_url: entry
+                .store_url
+                .unwrap_or_else(|| store.public_url(&entry.path)),
+            path: entry.path,
+            hash: entry.hash.unwrap_or_default(),
+            image_info: None,
+        });
+    }
+    if status.is_server_error() {
+        return Err(format!("HTTP error {status}").into());
+    }
+    if !status.is_success() {
+        return Err(NonRetryableError(format!("HTTP error {status}")).into());
+    }
+
+    let etag = header_str(&resp, reqwest::header::ETAG);
+    let last_modified = header_str(&resp, reqwest::header::LAST_MODIFIED);
+
+    let mime = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    let (bytes, ext, image_info, final_mime) = if let Some(cfg) = imaging {
+        let body = resp.bytes().await?;
+        let processed = imaging::process(&body, cfg)
+            .map_err(|e| NonRetryableError(format!("invalid image at {url}: {e}")))?;
+        let final_mime = if cfg.transcode_webp {
+            "image/webp".to_string()
+        } else {
+            mime.clone()
+        };
+        (
+            processed.bytes,
+            processed.ext,
+            Some(processed.info),
+            final_mime,
+        )
+    } else {
+        let mut buf = Vec::new();
+        let mut stream = resp.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        (buf, ext_for_mime(&mime), None, mime.clone())
+    };
+
+    let hash = format!("{:x}", Sha256::digest(&bytes));
+    let bytes_len = bytes.len() as u64;
+    let dir = content_address_dir(&hash).expect("a sha256 hex digest is always ASCII and 64 bytes");
+    let final_path = format!("{dir}/{hash}.{ext}");
+
+    if store.exists(&final_path).await? {
+        // Identical content already stored by another product.
+        let store_url = store.public_url(&final_path);
+        put_cache_entry(
+            url,
+            &CacheEntry {
+                path: final_path.clone(),
+                etag,
+                last_modified,
+                hash: Some(hash.clone()),
+                store_url: Some(store_url.clone()),
+            },
+        )?;
+        return Ok(ContentAddressedOutcome {
+            downloaded: false,
+            store_url,
+            path: final_path,
+            hash,
+            image_info,
+        });
+    }
+
+    let stored = store.store(&final_path, once_stream(bytes)).await?;
+    let metadata = ContentMetadata {
+        mime: &final_mime,
+        bytes: bytes_len,
+        original_url: url,
+    };
+    store
+        .store(
+            &format!("{final_path}.json"),
+            once_stream(serde_json::to_vec_pretty(&metadata)?),
+        )
+        .await?;
+
+    put_cache_entry(
+        url,
+        &CacheEntry {
+            path: final_path.clone(),
+            etag,
+            last_modified,
+            hash: Some(hash.clone()),
+            store_url: Some(stored.url.clone()),
+        },
+    )?;
+
+    Ok(ContentAddressedOutcome {
+        downloaded: true,
+        store_url: stored.url,
+        path: final_path,
+        hash,
+        image_info,
+    })
+}
+
+/// Map a `Content-Type` value to a file extension.
+fn ext_for_mime(mime: &str) -> &'static str {
+    match mime.split(';').next().unwrap_or("").trim() {
+        "image/png" => "png",
+        "image/webp" => "webp",
+        "image/gif" => "gif",
+        "image/bmp" => "bmp",
+        _ => "jpg",
+    }
+}
+
+/// A download failure that should never be retried (e.g. a 4xx response).
+#[derive(Debug)]
+struct NonRetryableError(String);
+
+impl std::fmt::Display for NonRetryableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NonRetryableError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_ext_replaces_existing_extension() {
+        assert_eq!(with_ext("photo.jpg", "webp"), "photo.webp");
+    }
+
+    #[test]
+    fn with_ext_appends_when_none_present() {
+        assert_eq!(with_ext("photo", "webp"), "photo.webp");
+    }
+
+    #[test]
+    fn ext_for_mime_known_types() {
+        assert_eq!(ext_for_mime("image/png"), "png");
+        assert_eq!(ext_for_mime("image/webp; charset=binary"), "webp");
+        assert_eq!(ext_for_mime("image/gif"), "gif");
+        assert_eq!(ext_for_mime("image/bmp"), "bmp");
+    }
+
+    #[test]
+    fn ext_for_mime_unknown_defaults_to_jpg() {
+        assert_eq!(ext_for_mime("application/octet-stream"), "jpg");
+    }
+
+    #[test]
+    fn content_address_dir_splits_hash_into_two_levels() {
+        assert_eq!(
+            content_address_dir("abcdef0123456789").as_deref(),
+            Some("images/ab/cd")
+        );
+    }
+
+    #[test]
+    fn content_address_dir_handles_short_hash() {
+        assert_eq!(content_address_dir("ab").as_deref(), Some("images/ab/"));
+    }
+
+    #[test]
+    fn content_address_dir_rejects_non_char_boundary_slicing() {
+        assert_eq!(content_address_dir("aébc"), None);
+    }
+
+    #[test]
+    fn is_retryable_non_retryable_error_is_false() {
+        let err: Box<dyn std::error::Error> =
+            NonRetryableError("HTTP error 404".to_string()).into();
+        assert!(!is_retryable(err.as_ref()));
+    }
+
+    #[test]
+    fn is_retryable_unknown_error_defaults_to_true() {
+        let err: Box<dyn std::error::Error> = "boom".into();
+        assert!(is_retryable(err.as_ref()));
+    }
+
+    #[test]
+    fn next_delay_doubles() {
+        assert_eq!(next_delay(Duration::from_secs(1)), Duration::from_secs(2));
+        assert_eq!(next_delay(Duration::from_secs(5)), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn next_delay_caps_at_max() {
+        assert_eq!(next_delay(MAX_RETRY_DELAY), MAX_RETRY_DELAY);
+        assert_eq!(
+            next_delay(MAX_RETRY_DELAY - Duration::from_secs(1)),
+            MAX_RETRY_DELAY
+        );
+    }
 }
@@ -0,0 +1,187 @@
+//! Pluggable storage backends for downloaded images, mirroring the split
+//! pict-rs makes between local-disk and object storage.
+
+use std::pin::Pin;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::Stream;
+
+type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, reqwest::Error>> + Send>>;
+
+/// Result of successfully persisting an object.
+pub struct StoredMedia {
+    pub bytes: u64,
+    pub url: String,
+}
+
+/// Where downloaded images end up. Implementations decide how a `key` (a
+/// relative path such as `brandname/file.jpg`) maps to a location and what
+/// public URL the stored object is reachable at.
+#[async_trait]
+pub trait MediaStore: Send + Sync {
+    /// Whether `key` is already stored.
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// The public URL `key` is (or will be) reachable at. Pure string
+    /// construction, so callers can use it without having stored anything
+    /// yet (e.g. a dedup hit against an already-stored key).
+    fn public_url(&self, key: &str) -> String;
+
+    /// Persist `stream`'s bytes under `key`, returning its byte length and
+    /// public URL.
+    async fn store(
+        &self,
+        key: &str,
+        stream: ByteStream,
+    ) -> Result<StoredMedia, Box<dyn std::error::Error>>;
+}
+
+/// Join a public URL base and a storage key, tolerating a trailing slash on
+/// `base` so callers don't have to normalize it themselves.
+fn join_public_url(base: &str, key: &str) -> String {
+    format!("{}/{key}", base.trim_end_matches('/'))
+}
+
+/// Local filesystem backend; matches the tool's original on-disk layout of
+/// writing to `<key>.tmp` and renaming into place on success.
+pub struct FileStore {
+    /// Public URL prefix images are served from once stored (e.g.
+    /// `https://portal.framescloud.optiserver.co.uk/images`).
+    pub public_url_base: String,
+}
+
+#[async_trait]
+impl MediaStore for FileStore {
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(tokio::fs::try_exists(key).await?)
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        join_public_url(&self.public_url_base, key)
+    }
+
+    async fn store(
+        &self,
+        key: &str,
+        mut stream: ByteStream,
+    ) -> Result<StoredMedia, Box<dyn std::error::Error>> {
+        use futures::StreamExt;
+        use tokio::io::AsyncWriteExt;
+
+        if let Some(parent) = std::path::Path::new(key).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let tmp_path = format!("{key}.tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        let mut bytes = 0u64;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            bytes += chunk.len() as u64;
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        drop(file);
+        tokio::fs::rename(&tmp_path, key).await?;
+
+        Ok(StoredMedia {
+            bytes,
+            url: self.public_url(key),
+        })
+    }
+}
+
+/// S3-compatible object storage backend, built on `object_store`. Lets users
+/// publish straight to a CDN-backed bucket without a second sync step.
+pub struct S3Store {
+    client: object_store::aws::AmazonS3,
+    public_url_base: String,
+}
+
+impl S3Store {
+    /// Build an `S3Store` for `bucket`, taking AWS credentials/region from
+    /// the environment (`AWS_ACCESS_KEY_ID`, `AWS_REGION`, etc).
+    /// `public_url_base` is the CDN/bucket URL images are served from.
+    pub fn new(bucket: &str, public_url_base: String) -> Result<Self, Box<dyn std::error::Error>> {
+        let client = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .build()?;
+        Ok(Self {
+            client,
+            public_url_base,
+        })
+    }
+}
+
+#[async_trait]
+impl MediaStore for S3Store {
+    async fn exists(&self, key: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        match self.client.head(&object_store::path::Path::from(key)).await {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn public_url(&self, key: &str) -> String {
+        join_public_url(&self.public_url_base, key)
+    }
+
+    async fn store(
+        &self,
+        key: &str,
+        mut stream: ByteStream,
+    ) -> Result<StoredMedia, Box<dyn std::error::Error>> {
+        use futures::StreamExt;
+
+        // object_store's single-shot `put` wants ownership of the bytes up
+        // front; images are small enough that buffering here is fine.
+        let mut buf = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            buf.extend_from_slice(&chunk?);
+        }
+        let bytes = buf.len() as u64;
+
+        self.client
+            .put(&object_store::path::Path::from(key), buf.into())
+            .await?;
+
+        Ok(StoredMedia {
+            bytes,
+            url: self.public_url(key),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_public_url_trims_trailing_slash_on_base() {
+        assert_eq!(
+            join_public_url("https://cdn.example.com/images/", "brand/a.jpg"),
+            "https://cdn.example.com/images/brand/a.jpg"
+        );
+    }
+
+    #[test]
+    fn join_public_url_leaves_base_without_trailing_slash_alone() {
+        assert_eq!(
+            join_public_url("https://cdn.example.com/images", "brand/a.jpg"),
+            "https://cdn.example.com/images/brand/a.jpg"
+        );
+    }
+
+    #[test]
+    fn filestore_public_url_delegates_to_join_public_url() {
+        let store = FileStore {
+            public_url_base: "https://cdn.example.com/images/".to_string(),
+        };
+        assert_eq!(
+            store.public_url("brand/a.jpg"),
+            "https://cdn.example.com/images/brand/a.jpg"
+        );
+    }
+}